@@ -1,258 +1,463 @@
-use crate::utils::tools::exec_cmd;
+use crate::utils::tools::{yes_no, CmdBuilder, IoMode};
 use log::error;
 use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
     fmt::Display,
-    fs::{read_link, read_to_string},
+    path::PathBuf,
 };
+use sysinfo::{Pid, ProcessRefreshKind, RefreshKind, Signal, System};
+
+/// Which PIDs of a [`PIDInfo::terminate_tree`] subtree were stopped, killed, or
+/// left alive. A PID that was stopped and then killed appears in both
+/// `stopped` and `killed`; `failed` holds every PID that was not killed,
+/// whether it was skipped at confirmation or a signal to it failed.
+#[derive(Debug, Default)]
+pub struct TerminationReport {
+    pub stopped: Vec<u64>,
+    pub killed: Vec<u64>,
+    pub failed: Vec<u64>,
+}
 
 pub struct PIDInfo {
     pub pid: u64,
-    pub exe: String,
+    pub exe: PathBuf,
     pub root: String,
     pub cwd: String,
-    pub cmdline: String,
-    pub environ: String,
+    pub cmdline: Vec<OsString>,
+    pub environ: Vec<OsString>,
+    pub cgroup: String,
+    pub namespaces: HashMap<String, String>,
+    is_namespace_init: bool,
 }
 
+/// cgroup path substrings left behind by the common container runtimes.
+const CONTAINER_MARKERS: [&str; 3] = ["docker", "containerd", "podman"];
+
 #[cfg(target_os = "linux")]
-impl PIDInfo {
-    pub fn new(pid: u64) -> Result<PIDInfo, Box<dyn std::error::Error>> {
-        let exe = read_link(format!("/proc/{}/exe", pid))?
-            .display()
-            .to_string();
-        let root = read_link(format!("/proc/{}/root", pid))?
-            .display()
-            .to_string();
-        let cwd = read_link(format!("/proc/{}/cwd", pid))?
-            .display()
-            .to_string();
-        let cmdline = read_to_string(format!("/proc/{}/cmdline", pid))?;
-        let environ = read_to_string(format!("/proc/{}/environ", pid))?;
-        Ok(PIDInfo {
-            pid,
-            exe,
-            root,
-            cwd,
-            cmdline,
-            environ,
-        })
+fn read_cgroup(pid: u64) -> std::io::Result<String> {
+    Ok(std::fs::read_to_string(format!("/proc/{}/cgroup", pid))?
+        .trim()
+        .to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_cgroup(_pid: u64) -> std::io::Result<String> {
+    Ok(String::new())
+}
+
+#[cfg(target_os = "linux")]
+fn read_namespaces(pid: u64) -> HashMap<String, String> {
+    let mut namespaces = HashMap::new();
+    if let Ok(entries) = std::fs::read_dir(format!("/proc/{}/ns", pid)) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Ok(link) = std::fs::read_link(entry.path()) {
+                namespaces.insert(name, link.display().to_string());
+            }
+        }
     }
+    namespaces
+}
 
-    pub fn terminate(&self) {
-        if !exec_cmd("kill", &["-9", &self.pid.to_string()], false)
-            .unwrap()
-            .wait()
-            .unwrap()
-            .success()
-        {
-            error!("Failed to terminate PID {}", &self.pid);
+#[cfg(target_os = "linux")]
+fn read_mnt_namespace(pid: u64) -> std::io::Result<String> {
+    std::fs::read_link(format!("/proc/{}/ns/mnt", pid)).map(|link| link.display().to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_mnt_namespace(_pid: u64) -> std::io::Result<String> {
+    Ok(String::new())
+}
+
+#[cfg(target_os = "linux")]
+fn read_our_mnt_namespace() -> std::io::Result<String> {
+    std::fs::read_link("/proc/self/ns/mnt").map(|link| link.display().to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_our_mnt_namespace() -> std::io::Result<String> {
+    Ok(String::new())
+}
+
+/// Whether `pid` is PID 1 of its own PID namespace, per the `NStgid` field
+/// of `/proc/<pid>/status` (the innermost entry is the PID as seen by the
+/// process itself).
+#[cfg(target_os = "linux")]
+fn read_is_namespace_init(pid: u64) -> std::io::Result<bool> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid))?;
+    Ok(status
+        .lines()
+        .find_map(|line| {
+            line.strip_prefix("NStgid:")
+                .and_then(|rest| rest.split_whitespace().last())
+                .map(|inner_pid| inner_pid == "1")
+        })
+        .unwrap_or(false))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_is_namespace_init(_pid: u64) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+/// Whether `pid`'s cgroup path reveals it's a Docker/podman/containerd scope.
+/// Errors (the file can no longer be read, e.g. the process exited or is
+/// behind a permission boundary) are propagated rather than swallowed, since
+/// an unreadable cgroup is exactly what a real container boundary looks like.
+fn pid_is_containerized(pid: u64) -> std::io::Result<bool> {
+    let cgroup = read_cgroup(pid)?;
+    Ok(CONTAINER_MARKERS
+        .iter()
+        .any(|marker| cgroup.contains(marker)))
+}
+
+/// Whether `pid` sits in a different mount namespace than the agent, which
+/// means its `exe` path may not point at the same file the process sees.
+/// Errors reading either namespace link are propagated rather than treated
+/// as "same namespace" — a process we can't introspect is the case this
+/// guard exists for.
+fn pid_in_different_mnt_namespace(pid: u64) -> std::io::Result<bool> {
+    let our_mnt = read_our_mnt_namespace()?;
+    let target_mnt = read_mnt_namespace(pid)?;
+    Ok(our_mnt != target_mnt)
+}
+
+/// Warns and asks for confirmation before an `action` (terminate/quarantine)
+/// is taken against `pid`, if it's a containerized process, a namespace's
+/// init, lives in a different mount namespace than the agent, or any of
+/// those can't be determined at all. Returns `true` if the caller should go
+/// ahead. Used both for the root PID of a [`PIDInfo`] and for each
+/// descendant while walking a subtree, so a container boundary crossed
+/// partway down a process tree isn't missed.
+fn confirm_pid_safe_to_act(pid: u64, action: &str) -> bool {
+    let confirm = |reason: String| {
+        error!("{}", reason);
+        yes_no(format!("Proceed with {} of PID {} anyway", action, pid))
+    };
+
+    match pid_is_containerized(pid).and_then(|containerized| {
+        read_is_namespace_init(pid).map(|is_init| containerized || is_init)
+    }) {
+        Ok(true) => {
+            return confirm(format!(
+                "PID {} looks like a container/namespace init process; {} it could corrupt the container runtime's state or trigger a respawn",
+                pid, action
+            ));
+        }
+        Ok(false) => {}
+        Err(e) => {
+            return confirm(format!(
+                "Could not determine whether PID {} is a container/namespace init process ({}); refusing to assume it's safe",
+                pid, e
+            ));
         }
     }
 
-    pub fn quarantine(&self) {
-        if !exec_cmd("mv", &[&self.exe, "./quarantine"], false)
-            .unwrap()
-            .wait()
-            .unwrap()
-            .success()
-        {
-            error!("Failed to move exe {}", &self.exe);
+    match pid_in_different_mnt_namespace(pid) {
+        Ok(true) => {
+            return confirm(format!(
+                "PID {} lives in a different mount namespace than this agent; its exe path may not resolve to the file the process actually sees",
+                pid
+            ));
         }
-        if !exec_cmd("chmod", &["444", &self.exe], false)
-            .unwrap()
-            .wait()
-            .unwrap()
-            .success()
-        {
-            error!("Failed to chmod exe {}", &self.exe);
+        Ok(false) => {}
+        Err(e) => {
+            return confirm(format!(
+                "Could not determine PID {}'s mount namespace ({}); refusing to assume it matches this agent's",
+                pid, e
+            ));
         }
     }
+
+    true
+}
+
+/// Depth-first walk of `children_of` rooted at `root`, returning every PID in
+/// the subtree in discovery order (the root first). Reversing the result
+/// puts children before their parents, which is what `terminate_tree` needs
+/// to kill leaves before the processes that could respawn them.
+fn subtree_pids(root: u64, children_of: &HashMap<u64, Vec<u64>>) -> Vec<u64> {
+    let mut subtree = Vec::new();
+    let mut stack = vec![root];
+    while let Some(pid) = stack.pop() {
+        subtree.push(pid);
+        if let Some(children) = children_of.get(&pid) {
+            stack.extend(children);
+        }
+    }
+    subtree
 }
 
-#[cfg(target_os = "freebsd")]
 impl PIDInfo {
     pub fn new(pid: u64) -> Option<PIDInfo> {
-        let exe_cmd = exec_cmd("procstat", &["-b", &pid.to_string()[..]], false)
-            .unwrap()
-            .wait_with_output()
-            .unwrap();
-        let exe_stdout = match exe_cmd.status.success() {
-            true => exe_cmd.stdout,
-            false => {
-                error!("Failed to get exe for PID {}", pid);
+        let sys = System::new_with_specifics(
+            RefreshKind::nothing().with_processes(ProcessRefreshKind::everything()),
+        );
+        let process = match sys.process(Pid::from_u32(pid as u32)) {
+            Some(process) => process,
+            None => {
+                error!("Failed to find PID {}", pid);
                 return None;
             }
         };
-        let exe_full = String::from_utf8_lossy(&exe_stdout);
-        let exe = exe_full.split_whitespace().last().unwrap();
+        Some(PIDInfo {
+            pid,
+            exe: process.exe().map(|p| p.to_path_buf()).unwrap_or_default(),
+            root: process
+                .root()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default(),
+            cwd: process
+                .cwd()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default(),
+            cmdline: process.cmd().to_vec(),
+            environ: process.environ().to_vec(),
+            cgroup: read_cgroup(pid).unwrap_or_default(),
+            #[cfg(target_os = "linux")]
+            namespaces: read_namespaces(pid),
+            #[cfg(not(target_os = "linux"))]
+            namespaces: HashMap::new(),
+            is_namespace_init: read_is_namespace_init(pid).unwrap_or(false),
+        })
+    }
 
-        let cwd_cmd = exec_cmd("procstat", &["pwdx", &pid.to_string()[..]], false)
-            .unwrap()
-            .wait_with_output()
-            .unwrap();
-        let cwd_stdout = match cwd_cmd.status.success() {
-            true => cwd_cmd.stdout,
-            false => {
-                error!("Failed to get cwd for PID {}", pid);
-                return None;
-            }
-        };
-        let cwd_full = String::from_utf8_lossy(&cwd_stdout);
-        let cwd = cwd_full.split_whitespace().last().unwrap();
+    /// Whether this process's cgroup path reveals it's a Docker/podman/containerd scope.
+    /// Returns `false` if the cgroup file couldn't even be read; callers that
+    /// need a fail-closed answer should go through `confirm_safe_to_act` instead.
+    pub fn is_containerized(&self) -> bool {
+        pid_is_containerized(self.pid).unwrap_or(false)
+    }
 
-        let cmdline_cmd = exec_cmd("procstat", &["pargs", &pid.to_string()[..]], false)
-            .unwrap()
-            .wait_with_output()
-            .unwrap();
-        let cmdline_stdout = match cmdline_cmd.status.success() {
-            true => cmdline_cmd.stdout,
-            false => {
-                error!("Failed to get cmdline for PID {}", pid);
-                return None;
-            }
-        };
-        let cmdline_full = String::from_utf8_lossy(&cmdline_stdout);
-        let mut cmdline: Vec<String> = Vec::new();
-        for line in cmdline_full.split('\n') {
-            cmdline.push(line.split_once(':').unwrap().1.trim().to_owned());
-        }
-        cmdline.remove(0);
+    /// Whether this process is PID 1 of its own PID namespace.
+    pub fn is_namespace_init(&self) -> bool {
+        self.is_namespace_init
+    }
 
-        let environ_cmd = exec_cmd("procstat", &["penv", &pid.to_string()[..]], false)
-            .unwrap()
-            .wait_with_output()
-            .unwrap();
-        let environ_stdout = match environ_cmd.status.success() {
-            true => environ_cmd.stdout,
-            false => {
-                error!("Failed to get environ for PID {}", pid);
-                return None;
-            }
-        };
-        let environ_full = String::from_utf8_lossy(&environ_stdout);
-        let mut environ: Vec<String> = Vec::new();
-        for line in environ_full.split('\n') {
-            environ.push(line.split_once(':').unwrap().1.trim().to_owned());
-        }
-        environ.remove(0);
+    /// The namespace kind (`pid`, `net`, `mnt`, ...) to its `kind:[inode]` id.
+    pub fn namespace_ids(&self) -> &HashMap<String, String> {
+        &self.namespaces
+    }
 
-        Some(PIDInfo {
-            pid,
-            exe: exe.to_string(), // -b
-            root: String::from("N/A"),
-            cwd: cwd.to_string(),              // pwdx
-            cmdline: format!("{:?}", cmdline), // pargs
-            environ: format!("{:?}", environ), // penv
-        })
+    /// Guards `terminate`/`quarantine` against blindly acting on a
+    /// containerized process or a namespace's init, warning and requiring
+    /// confirmation before proceeding. Returns `true` if the caller should go ahead.
+    fn confirm_safe_to_act(&self, action: &str) -> bool {
+        confirm_pid_safe_to_act(self.pid, action)
     }
 
     pub fn terminate(&self) {
-        if !exec_cmd("kill", &["-9", &self.pid.to_string()], false)
-            .unwrap()
-            .wait()
-            .unwrap()
-            .success()
-        {
+        if !self.confirm_safe_to_act("terminating") {
+            return;
+        }
+        let sys = System::new_with_specifics(
+            RefreshKind::nothing().with_processes(ProcessRefreshKind::everything()),
+        );
+        let killed = sys
+            .process(Pid::from_u32(self.pid as u32))
+            .map(|process| process.kill())
+            .unwrap_or(false);
+        if !killed {
             error!("Failed to terminate PID {}", &self.pid);
         }
     }
 
-    pub fn quarantine(&self) {
-        if !exec_cmd("mv", &[&self.exe, "./quarantine"], false)
-            .unwrap()
-            .wait()
-            .unwrap()
-            .success()
-        {
-            error!("Failed to move exe {}", &self.exe);
+    /// Kills this PID's entire process subtree, so a supervisor can't respawn
+    /// a killed child and a killed parent doesn't orphan still-running children.
+    ///
+    /// The whole subtree is SIGSTOPed first to freeze it against forking
+    /// during teardown, then SIGKILLed from the leaves up to `self.pid`.
+    pub fn terminate_tree(&self) -> TerminationReport {
+        let mut report = TerminationReport::default();
+        if !self.confirm_safe_to_act("terminating the process tree of") {
+            report.failed.push(self.pid);
+            return report;
         }
-        if !exec_cmd("chmod", &["444", &self.exe], false)
-            .unwrap()
-            .wait()
-            .unwrap()
-            .success()
-        {
-            error!("Failed to chmod exe {}", &self.exe);
+
+        let sys = System::new_with_specifics(
+            RefreshKind::nothing().with_processes(ProcessRefreshKind::everything()),
+        );
+
+        let mut children_of: HashMap<u64, Vec<u64>> = HashMap::new();
+        for (child_pid, process) in sys.processes() {
+            if let Some(parent_pid) = process.parent() {
+                children_of
+                    .entry(parent_pid.as_u32() as u64)
+                    .or_default()
+                    .push(child_pid.as_u32() as u64);
+            }
         }
-    }
-}
 
-#[cfg(target_os = "windows")]
-impl PIDInfo {
-    pub fn new(pid: u64) -> Option<PIDInfo> {
-        let mut out = PIDInfo {
-            pid,
-            exe: String::from("N/A"),
-            root: String::from("N/A"),
-            cwd: String::from("N/A"),
-            cmdline: String::from("N/A"),
-            environ: String::from("N/A"),
-        };
-        let exe_cmd = exec_cmd("powershell", &["-ExecutionPolicy", "Bypass", &format!("Get-WmiObject Win32_Process -Filter \"ProcessId = {}\" | Select-Object ExecutablePath, CommandLine | Format-List", pid)], false)
-            .unwrap()
-            .wait_with_output()
-            .unwrap();
-        let exe_stdout = match exe_cmd.status.success() {
-            true => exe_cmd.stdout,
-            false => {
-                error!("Failed to get process info");
-                return None;
+        let subtree = subtree_pids(self.pid, &children_of);
+
+        // The root PID was already cleared by `confirm_safe_to_act` above;
+        // check every descendant too, since the subtree can cross into a
+        // container or namespace boundary partway down.
+        let mut skip: HashSet<u64> = HashSet::new();
+        for &pid in &subtree {
+            if pid != self.pid && !confirm_pid_safe_to_act(pid, "terminating") {
+                skip.insert(pid);
+                report.failed.push(pid);
             }
-        };
-        let exe = String::from_utf8_lossy(&exe_stdout);
-
-        let comps: Vec<&str> = exe.split("\r\n").collect();
-        for comp in comps {
-            match comp.split_once(':') {
-                Some(key_val) => {
-                    let key = key_val.0.trim();
-                    let val = key_val.1.trim().to_owned();
-                    match key {
-                        "ExecutablePath" => {
-                            out.exe = val;
-                        }
-                        "CommandLine" => {
-                            out.cmdline = val;
-                        }
-                        _ => {}
+        }
+
+        for &pid in &subtree {
+            if skip.contains(&pid) {
+                continue;
+            }
+            match sys.process(Pid::from_u32(pid as u32)) {
+                Some(process) if process.kill_with(Signal::Stop).unwrap_or(false) => {
+                    report.stopped.push(pid);
+                }
+                _ => report.failed.push(pid),
+            }
+        }
+
+        // Reversing the DFS discovery order puts children before their
+        // parents, so leaves die before the processes that could respawn them.
+        for &pid in subtree.iter().rev() {
+            if skip.contains(&pid) {
+                continue;
+            }
+            match sys.process(Pid::from_u32(pid as u32)) {
+                Some(process) if process.kill() => report.killed.push(pid),
+                _ => {
+                    if !report.failed.contains(&pid) {
+                        report.failed.push(pid);
                     }
                 }
-                None => {}
             }
         }
-        Some(out)
+
+        // A PID that failed to SIGSTOP can still succeed at SIGKILL; keep
+        // `failed` and `killed` mutually exclusive so `killed` is authoritative.
+        report.failed.retain(|pid| !report.killed.contains(pid));
+
+        report
     }
 
-    pub fn terminate(&self) {
-        if !exec_cmd("taskkill", &["/PID", &self.pid.to_string(), "/F"], false)
+    #[cfg(target_os = "windows")]
+    pub fn quarantine(&self) {
+        if !self.confirm_safe_to_act("quarantining") {
+            return;
+        }
+        if !CmdBuilder::new("move")
+            .arg(&self.exe)
+            .arg(".\\quarantine")
+            .stdout(IoMode::Piped)
+            .stderr(IoMode::Piped)
+            .spawn()
             .unwrap()
             .wait()
             .unwrap()
             .success()
         {
-            error!("Failed to terminate PID {}", &self.exe);
+            error!("Failed to move exe {}", self.exe.display());
         }
+        println!("Please revoke all execution access, or get this thing out of here");
     }
 
+    #[cfg(not(target_os = "windows"))]
     pub fn quarantine(&self) {
-        if !exec_cmd("move", &[&self.exe, ".\\quarantine"], false)
+        if !self.confirm_safe_to_act("quarantining") {
+            return;
+        }
+        if !CmdBuilder::new("mv")
+            .arg(&self.exe)
+            .arg("./quarantine")
+            .stdout(IoMode::Piped)
+            .stderr(IoMode::Piped)
+            .spawn()
             .unwrap()
             .wait()
             .unwrap()
             .success()
         {
-            error!("Failed to move exe {}", &self.exe);
+            error!("Failed to move exe {}", self.exe.display());
+        }
+        if !CmdBuilder::new("chmod")
+            .arg("444")
+            .arg(&self.exe)
+            .stdout(IoMode::Piped)
+            .stderr(IoMode::Piped)
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap()
+            .success()
+        {
+            error!("Failed to chmod exe {}", self.exe.display());
         }
-        println!("Please revoke all execution access, or get this thing out of here");
     }
 }
 
 impl Display for PIDInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let cmdline = self
+            .cmdline
+            .iter()
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
         write!(
             f,
             "{} | {} | {} | {} | {}",
-            self.pid, self.exe, self.root, self.cwd, self.cmdline
+            self.pid,
+            self.exe.display(),
+            self.root,
+            self.cwd,
+            cmdline
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn children_map(edges: &[(u64, u64)]) -> HashMap<u64, Vec<u64>> {
+        let mut children_of: HashMap<u64, Vec<u64>> = HashMap::new();
+        for &(parent, child) in edges {
+            children_of.entry(parent).or_default().push(child);
+        }
+        children_of
+    }
+
+    #[test]
+    fn subtree_pids_visits_every_descendant() {
+        // 1 -> 2 -> 4
+        //   -> 3
+        let children_of = children_map(&[(1, 2), (1, 3), (2, 4)]);
+        let mut subtree = subtree_pids(1, &children_of);
+        subtree.sort_unstable();
+        assert_eq!(subtree, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn subtree_pids_stops_at_leaves_with_no_children() {
+        let children_of = children_map(&[(1, 2)]);
+        assert_eq!(subtree_pids(2, &children_of), vec![2]);
+    }
+
+    #[test]
+    fn subtree_pids_ignores_other_trees() {
+        let children_of = children_map(&[(1, 2), (10, 11)]);
+        let mut subtree = subtree_pids(1, &children_of);
+        subtree.sort_unstable();
+        assert_eq!(subtree, vec![1, 2]);
+    }
+
+    #[test]
+    fn reversed_discovery_order_puts_children_before_parents() {
+        // 1 -> 2 -> 3
+        let children_of = children_map(&[(1, 2), (2, 3)]);
+        let reversed: Vec<u64> = subtree_pids(1, &children_of).into_iter().rev().collect();
+        let pos = |pid: u64| reversed.iter().position(|&p| p == pid).unwrap();
+
+        // Every descendant must be signalled strictly before its ancestor,
+        // so a kill pass never respawns into a process it hasn't reached yet.
+        assert!(pos(2) < pos(1));
+        assert!(pos(3) < pos(2));
+    }
+}
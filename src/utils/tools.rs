@@ -1,7 +1,8 @@
 use get_if_addrs::{get_if_addrs, Interface};
 use rpassword::prompt_password;
 use sha1::{Digest, Sha1};
-use std::process::{Command, Stdio};
+use std::ffi::OsStr;
+use std::process::{Command, Output, Stdio};
 use std::{
     fs::File,
     io::{self, stdin, stdout, BufRead, BufReader, Read, Write},
@@ -9,6 +10,89 @@ use std::{
     process::Child,
 };
 
+/// How a child process's stdin/stdout/stderr should be wired up.
+pub enum IoMode {
+    Piped,
+    Null,
+    Inherit,
+}
+
+impl From<IoMode> for Stdio {
+    fn from(mode: IoMode) -> Self {
+        match mode {
+            IoMode::Piped => Stdio::piped(),
+            IoMode::Null => Stdio::null(),
+            IoMode::Inherit => Stdio::inherit(),
+        }
+    }
+}
+
+/// Builder for child processes, taking `impl AsRef<OsStr>` everywhere so
+/// non-UTF-8 paths and arguments can be passed through untouched.
+pub struct CmdBuilder {
+    command: Command,
+}
+
+impl CmdBuilder {
+    pub fn new(cmd: impl AsRef<OsStr>) -> CmdBuilder {
+        CmdBuilder {
+            command: Command::new(cmd),
+        }
+    }
+
+    pub fn arg(mut self, arg: impl AsRef<OsStr>) -> CmdBuilder {
+        self.command.arg(arg);
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> CmdBuilder
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.command.args(args);
+        self
+    }
+
+    pub fn env(mut self, key: impl AsRef<OsStr>, val: impl AsRef<OsStr>) -> CmdBuilder {
+        self.command.env(key, val);
+        self
+    }
+
+    pub fn envs<I, K, V>(mut self, vars: I) -> CmdBuilder
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.command.envs(vars);
+        self
+    }
+
+    pub fn stdin(mut self, mode: IoMode) -> CmdBuilder {
+        self.command.stdin(mode);
+        self
+    }
+
+    pub fn stdout(mut self, mode: IoMode) -> CmdBuilder {
+        self.command.stdout(mode);
+        self
+    }
+
+    pub fn stderr(mut self, mode: IoMode) -> CmdBuilder {
+        self.command.stderr(mode);
+        self
+    }
+
+    pub fn spawn(mut self) -> io::Result<Child> {
+        self.command.spawn()
+    }
+
+    pub fn output(mut self) -> io::Result<Output> {
+        self.command.output()
+    }
+}
+
 pub fn verify_config(path: String) -> bool {
     yes_no(format!("Is config hash ok: {}", sha1sum(path).unwrap()))
 }
@@ -45,13 +129,13 @@ pub fn get_interface_and_ip() -> Interface {
 }
 
 pub fn exec_cmd(cmd: &str, args: &[&str], stdin_req: bool) -> Result<Child, io::Error> {
-    Command::new(cmd)
+    CmdBuilder::new(cmd)
         .args(args)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
+        .stdout(IoMode::Piped)
+        .stderr(IoMode::Piped)
         .stdin(match stdin_req {
-            true => Stdio::piped(),
-            false => Stdio::null(),
+            true => IoMode::Piped,
+            false => IoMode::Null,
         })
         .spawn()
 }